@@ -0,0 +1,108 @@
+//! Compile-time companion to `regex-impl`: `regex!("pattern")` runs the
+//! usual `tokenize` + `from_tokens` pipeline at macro-expansion time and
+//! emits the resulting state table as Rust source instead of a string, so a
+//! malformed pattern is a compile error and matching never re-tokenizes or
+//! re-compiles at runtime.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use regex_impl::{
+	nondeterministic::{MatchValue, Regex, State},
+	tokenize::NamedClass,
+};
+use syn::{parse_macro_input, LitStr};
+
+/// Expands to a `std::sync::LazyLock<regex_impl::nondeterministic::Regex>`
+/// expression, so it can be assigned straight into a `static`:
+///
+/// ```ignore
+/// static GREETING: std::sync::LazyLock<regex_impl::nondeterministic::Regex> =
+///     regex_impl_macros::regex!("^(hi|hello)$");
+/// ```
+///
+/// `pattern` is compiled here, at macro-expansion time; a pattern that
+/// `Regex::from_simple_expression` would reject is a compile error pointing
+/// at the string literal, not a panic at runtime.
+#[proc_macro]
+pub fn regex(input: TokenStream) -> TokenStream {
+	let pattern = parse_macro_input!(input as LitStr);
+
+	let compiled = match Regex::from_simple_expression(&pattern.value()) {
+		Ok(compiled) => compiled,
+		Err(err) => {
+			return syn::Error::new(pattern.span(), err.to_string())
+				.to_compile_error()
+				.into()
+		}
+	};
+
+	let (states, head, beginning_boundary, end_boundary, group_count) = compiled.into_parts();
+	let states = states.iter().map(state_tokens);
+
+	quote! {
+		::std::sync::LazyLock::new(|| {
+			::regex_impl::nondeterministic::Regex::from_parts(
+				::std::vec![#(#states),*],
+				#head,
+				#beginning_boundary,
+				#end_boundary,
+				#group_count,
+			)
+		})
+	}
+	.into()
+}
+
+fn state_tokens(state: &State) -> TokenStream2 {
+	let value = match_value_tokens(&state.value);
+	let next = state.next;
+
+	quote! {
+		::regex_impl::nondeterministic::State { value: #value, next: #next }
+	}
+}
+
+fn match_value_tokens(value: &MatchValue) -> TokenStream2 {
+	match value {
+		MatchValue::Char(c) => quote! { ::regex_impl::nondeterministic::MatchValue::Char(#c) },
+		MatchValue::Wildcard => quote! { ::regex_impl::nondeterministic::MatchValue::Wildcard },
+		MatchValue::Split { branch } => quote! {
+			::regex_impl::nondeterministic::MatchValue::Split { branch: #branch }
+		},
+		MatchValue::Save(slot) => {
+			quote! { ::regex_impl::nondeterministic::MatchValue::Save(#slot) }
+		}
+		MatchValue::Class {
+			ranges,
+			named,
+			negated,
+		} => {
+			let ranges = ranges.iter().map(|(lo, hi)| quote! { (#lo, #hi) });
+			let named = named.iter().map(|n| named_class_tokens(*n));
+
+			quote! {
+				::regex_impl::nondeterministic::MatchValue::Class {
+					ranges: ::std::boxed::Box::new([#(#ranges),*]),
+					named: ::std::boxed::Box::new([#(#named),*]),
+					negated: #negated,
+				}
+			}
+		}
+		MatchValue::Match => quote! { ::regex_impl::nondeterministic::MatchValue::Match },
+	}
+}
+
+fn named_class_tokens(class: NamedClass) -> TokenStream2 {
+	let variant = match class {
+		NamedClass::Alpha => quote! { Alpha },
+		NamedClass::Digit => quote! { Digit },
+		NamedClass::Alnum => quote! { Alnum },
+		NamedClass::Space => quote! { Space },
+		NamedClass::Upper => quote! { Upper },
+		NamedClass::Lower => quote! { Lower },
+		NamedClass::Punct => quote! { Punct },
+	};
+
+	quote! { ::regex_impl::tokenize::NamedClass::#variant }
+}
@@ -0,0 +1,2 @@
+pub mod nondeterministic;
+pub mod tokenize;
@@ -1,5 +1,5 @@
-mod nondeterministic;
-mod tokenize;
+use regex_impl::nondeterministic;
+
 fn main() {
 	let regex = nondeterministic::Regex::from_simple_expression("^(abc(cd)+)?c$")
 		.expect("Regex compiling failed!");
@@ -1,33 +1,93 @@
 use std::fmt;
 
-use crate::tokenize::{self, MatchCharacter, Repeat, Token};
+use crate::tokenize::{self, MatchCharacter, NamedClass, Repeat, Token};
+
+/// Caps the number of states a single pattern may expand into, so a
+/// pathological bounded repetition (e.g. `a{1000}{1000}`) returns an error
+/// instead of exhausting memory.
+const MAX_STATES: usize = 1 << 16;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+	Tokenize(tokenize::Error),
+	/// the pattern expanded past [`MAX_STATES`] states while compiling a
+	/// bounded repetition
+	TooManyStates,
+}
+
+impl From<tokenize::Error> for Error {
+	fn from(err: tokenize::Error) -> Self {
+		Self::Tokenize(err)
+	}
+}
 
-#[derive(Debug, Clone, PartialEq, Copy)]
-enum MatchValue {
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Tokenize(err) => write!(f, "{err}"),
+			Self::TooManyStates => write!(f, "pattern expands to too many states"),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// A single NFA transition. Public (and hidden from docs) only so the
+/// `regex!` proc-macro can emit a state table literally; not part of the
+/// stable API.
+#[doc(hidden)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum MatchValue {
 	Char(char),
 	/// `branch` must always be valid
 	Split {
 		branch: usize,
 	},
+	/// records the current input position into capture slot `usize`, then
+	/// proceeds to `next` without consuming any input. Slots `0`/`1` hold the
+	/// span of the whole match; an explicit group `n` (0-based) uses slots
+	/// `2 * (n + 1)` and `2 * (n + 1) + 1`.
+	Save(usize),
 	Wildcard,
+	Class {
+		ranges: Box<[(char, char)]>,
+		named: Box<[NamedClass]>,
+		negated: bool,
+	},
 	Match,
 }
 
 impl<'a> MatchValue {
-	/// Assumes MatchValue is either `Char` or `Wildcard`
+	/// Assumes MatchValue is `Char`, `Wildcard`, or `Class`
 	fn matches(&self, value: char) -> bool {
 		match self {
 			MatchValue::Char(c) => *c == value,
 			MatchValue::Wildcard => true,
-			_ => unreachable!("called MatchValue::matches() on MatchValue::Split"),
+			MatchValue::Class {
+				ranges,
+				named,
+				negated,
+			} => {
+				let in_class = ranges.iter().any(|&(lo, hi)| lo <= value && value <= hi)
+					|| named.iter().any(|n| n.matches(value));
+
+				in_class != *negated
+			}
+			_ => unreachable!("called MatchValue::matches() on a non-consuming MatchValue"),
 		}
 	}
 }
 
+/// One entry in a `Regex`'s state table. Public (and hidden from docs) only
+/// so the `regex!` proc-macro can emit a state table literally; not part of
+/// the stable API.
+#[doc(hidden)]
 #[derive(Debug, Clone, PartialEq)]
-struct State {
-	value: MatchValue,
-	next: usize,
+pub struct State {
+	pub value: MatchValue,
+	pub next: usize,
 }
 
 #[derive(Clone, Default, PartialEq)]
@@ -36,14 +96,16 @@ pub struct Regex {
 	head: usize,
 	beginning_boundary: bool,
 	end_boundary: bool,
+	/// the number of explicit capturing groups in the pattern
+	group_count: usize,
 }
 
 impl fmt::Debug for Regex {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		write!(
 			f,
-			"Regex {{\n head: {},\n ^: {},\n $: {},\n states: [\n",
-			self.head, self.beginning_boundary, self.end_boundary
+			"Regex {{\n head: {},\n ^: {},\n $: {},\n groups: {},\n states: [\n",
+			self.head, self.beginning_boundary, self.end_boundary, self.group_count
 		)?;
 
 		for (i, state) in self.states.iter().enumerate().rev() {
@@ -54,176 +116,535 @@ impl fmt::Debug for Regex {
 	}
 }
 
+/// A thread's capture slots: `slots[2 * n]`/`slots[2 * n + 1]` hold the
+/// (start, end) input positions of group `n`, or `None` if that group
+/// hasn't been entered/closed by this thread yet.
+type Slots = Box<[Option<usize>]>;
+
+/// Submatch spans produced by [`Regex::captures`], indexed by capture group
+/// number. Group `0` is always the whole match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Captures {
+	spans: Box<[Option<(usize, usize)>]>,
+}
+
+impl Captures {
+	fn from_slots(slots: &[Option<usize>]) -> Self {
+		let spans = slots
+			.chunks_exact(2)
+			.map(|pair| pair[0].zip(pair[1]))
+			.collect::<Vec<_>>()
+			.into_boxed_slice();
+
+		Self { spans }
+	}
+
+	/// Returns the `(start, end)` char-index span of capture group `group`
+	/// (`0` is the whole match), or `None` if that group didn't participate
+	/// in the match.
+	pub fn get(&self, group: usize) -> Option<(usize, usize)> {
+		self.spans.get(group).copied().flatten()
+	}
+
+	/// The number of groups tracked, including the whole-match group `0`.
+	pub fn len(&self) -> usize {
+		self.spans.len()
+	}
+
+	/// Always `false`: a successful match always has at least the
+	/// whole-match group `0`.
+	pub fn is_empty(&self) -> bool {
+		self.spans.is_empty()
+	}
+}
+
 #[derive(Debug, Default)]
 struct Step<'a> {
 	states: &'a [State],
-	// TODO: switch to bit field for space efficiency - Vec<u8>
-	current: Vec<bool>,
-	next: Vec<bool>,
-	matched: bool,
+	/// states already reached by the epsilon-closure under construction,
+	/// reset alongside `next` at the start of each `step`. Without this, an
+	/// epsilon cycle (e.g. from nesting unbounded quantifiers like `(a*)*`)
+	/// would make `add_state` recurse forever.
+	visited: Vec<bool>,
+	current: Vec<Option<Slots>>,
+	next: Vec<Option<Slots>>,
+	matched: Option<Slots>,
 }
 
 impl<'a> Step<'a> {
 	fn new(states: &'a [State]) -> Self {
 		Self {
 			states,
-			current: vec![false; states.len()],
-			next: vec![false; states.len()],
-			matched: false,
+			visited: vec![false; states.len()],
+			current: vec![None; states.len()],
+			next: vec![None; states.len()],
+			matched: None,
 		}
 	}
 
-	fn add_state(&mut self, idx: usize) {
+	/// Adds a thread with capture state `slots`, currently at input position
+	/// `pos`, to the epsilon-closure being built for this step. Earlier
+	/// (higher-priority) threads win when two threads reach the same state,
+	/// and a state already reached this step is skipped outright.
+	fn add_state(&mut self, idx: usize, slots: Slots, pos: usize) {
+		if self.visited[idx] {
+			return;
+		}
+		self.visited[idx] = true;
+
 		let state = &self.states[idx];
 
-		match state.value {
-			MatchValue::Char(_) | MatchValue::Wildcard => {
-				self.next[idx] = true;
+		match &state.value {
+			MatchValue::Char(_) | MatchValue::Wildcard | MatchValue::Class { .. } => {
+				if self.next[idx].is_none() {
+					self.next[idx] = Some(slots);
+				}
 			}
 			MatchValue::Split { branch } => {
-				self.add_state(branch);
-				self.add_state(state.next);
+				let branch = *branch;
+				self.add_state(branch, slots.clone(), pos);
+				self.add_state(state.next, slots, pos);
+			}
+			MatchValue::Save(slot) => {
+				let mut slots = slots;
+				slots[*slot] = Some(pos);
+				self.add_state(state.next, slots, pos);
+			}
+			MatchValue::Match => {
+				if self.matched.is_none() {
+					self.matched = Some(slots);
+				}
 			}
-			MatchValue::Match => self.matched = true,
 		};
 	}
 
-	fn step(&mut self, to_match: char) {
+	fn step(&mut self, to_match: char, pos: usize) {
 		std::mem::swap(&mut self.current, &mut self.next);
 
-		// TODO: better way to do this
 		for v in &mut self.next {
+			*v = None;
+		}
+		for v in &mut self.visited {
 			*v = false;
 		}
 
+		let states = self.states;
 		let next_states: Vec<_> = self
 			.current
-			.iter()
+			.iter_mut()
 			.enumerate()
-			.filter(|(_, x)| **x)
-			.map(|(i, _)| &self.states[i])
-			.filter(|state| state.value.matches(to_match))
-			.map(|s| s.next)
+			.filter_map(|(i, slots)| {
+				let slots = slots.take()?;
+				states[i]
+					.value
+					.matches(to_match)
+					.then(|| (states[i].next, slots))
+			})
 			.collect();
 
-		for next in next_states {
-			self.add_state(next);
+		for (next, slots) in next_states {
+			self.add_state(next, slots, pos);
 		}
 	}
 }
 
 impl Regex {
-	pub fn from_simple_expression(expression: &str) -> tokenize::Result<Self> {
-		Token::tokenize_regex(expression).map(|toks| Self::from_tokens(&toks))
+	pub fn from_simple_expression(expression: &str) -> Result<Self> {
+		Self::from_tokens(&tokenize::Tokens::tokenize_regex(expression)?)
+	}
+
+	/// Builds a `Regex` directly from an already-compiled state table,
+	/// bypassing tokenizing and compiling entirely. This is the inverse of
+	/// [`Self::into_parts`], used by the `regex!` proc-macro to rebuild a
+	/// pattern it compiled at macro-expansion time; not meant to be called
+	/// directly.
+	#[doc(hidden)]
+	pub fn from_parts(
+		states: Vec<State>,
+		head: usize,
+		beginning_boundary: bool,
+		end_boundary: bool,
+		group_count: usize,
+	) -> Self {
+		Self {
+			states,
+			head,
+			beginning_boundary,
+			end_boundary,
+			group_count,
+		}
+	}
+
+	/// Decomposes into the raw pieces [`Self::from_parts`] needs to rebuild
+	/// an equivalent `Regex`. Used by the `regex!` proc-macro to read out a
+	/// pattern compiled at macro-expansion time so it can emit the state
+	/// table as Rust source; not meant to be called directly.
+	#[doc(hidden)]
+	pub fn into_parts(self) -> (Vec<State>, usize, bool, bool, usize) {
+		(
+			self.states,
+			self.head,
+			self.beginning_boundary,
+			self.end_boundary,
+			self.group_count,
+		)
+	}
+
+	/// Compiles a single occurrence of `value`, chaining it onto whatever
+	/// `*index` currently points at.
+	fn convert_value(
+		value: &MatchCharacter,
+		states: &mut Vec<State>,
+		index: &mut usize,
+	) -> Result<()> {
+		macro_rules! push {
+			($state: expr) => {{
+				if states.len() >= MAX_STATES {
+					return Err(Error::TooManyStates);
+				}
+				states.push($state);
+				// not `*index += 1`: an `Or` arm rewinds `*index` back to a
+				// shared join point before compiling its second branch, so
+				// `*index` no longer tracks `states.len() - 1` by the time
+				// that branch starts pushing
+				*index = states.len() - 1;
+			}};
+		}
+
+		match value {
+			MatchCharacter::Char(c) => {
+				push!(State {
+					value: MatchValue::Char(*c),
+					next: *index
+				});
+			}
+			MatchCharacter::Wildcard => {
+				push!(State {
+					value: MatchValue::Wildcard,
+					next: *index
+				});
+			}
+			MatchCharacter::String(tokens) => Self::convert_tokens(tokens, states, index)?,
+			MatchCharacter::Group {
+				index: group_index,
+				tokens,
+			} => {
+				let group_index = *group_index;
+
+				// built back-to-front, like everything else here: the save
+				// that closes the group comes first (chaining to whatever
+				// follows the group), then the group's body, then the save
+				// that opens it
+				push!(State {
+					value: MatchValue::Save(2 * (group_index + 1) + 1),
+					next: *index,
+				});
+				Self::convert_tokens(tokens, states, index)?;
+				push!(State {
+					value: MatchValue::Save(2 * (group_index + 1)),
+					next: *index,
+				});
+			}
+			MatchCharacter::Class {
+				ranges,
+				named,
+				negated,
+			} => {
+				push!(State {
+					value: MatchValue::Class {
+						ranges: ranges.clone(),
+						named: named.clone(),
+						negated: *negated,
+					},
+					next: *index
+				});
+			}
+			MatchCharacter::Or(a, b) => {
+				// both arms must chain onto the same continuation, so capture
+				// it once and rewind `*index` back to it between arms instead
+				// of letting `a` chain onto wherever `b` ended up
+				let join = *index;
+
+				// build the second arm first so its head is known when we
+				// wire up the `Split` that chooses between the two arms
+				Self::convert_tokens(std::slice::from_ref(b), states, index)?;
+				let branch_b = *index;
+
+				*index = join;
+				Self::convert_tokens(std::slice::from_ref(a), states, index)?;
+				let branch_a = *index;
+
+				push!(State {
+					value: MatchValue::Split { branch: branch_a },
+					next: branch_b,
+				});
+			}
+		}
+
+		Ok(())
 	}
 
-	fn convert_tokens(tokens: &[Box<Token>], states: &mut Vec<State>, index: &mut usize) {
+	/// Compiles `token`, expanding its `repeat` into the states needed to
+	/// match it the required number of times, chaining the whole thing onto
+	/// whatever `*index` currently points at.
+	fn convert_token(token: &Token, states: &mut Vec<State>, index: &mut usize) -> Result<()> {
 		macro_rules! push {
-			($state: expr) => {
+			($state: expr) => {{
+				if states.len() >= MAX_STATES {
+					return Err(Error::TooManyStates);
+				}
 				states.push($state);
-				*index += 1;
-			};
+				// see the matching comment in convert_value: `*index` can be
+				// rewound to a join point by a nested `Or`, so it must be
+				// recomputed from `states.len()`, not incremented
+				*index = states.len() - 1;
+			}};
 		}
 
-		for token in tokens.into_iter().rev() {
-			let next = *index;
-			if let Repeat::AtLeast(_) = token.repeat {
+		match token.repeat {
+			Repeat::Exactly(n) => {
+				for _ in 0..n {
+					Self::convert_value(&token.value, states, index)?;
+				}
+			}
+			Repeat::Optional => {
+				let rest = *index;
+				Self::convert_value(&token.value, states, index)?;
+				let head = *index;
+
 				push!(State {
-					// fill later
+					value: MatchValue::Split { branch: rest },
+					next: head,
+				});
+			}
+			Repeat::AtLeast(min) => {
+				// the last (unbounded) occurrence loops back on itself via a
+				// `Split` that either repeats the occurrence or exits to `rest`
+				let rest = *index;
+				push!(State {
+					// fill in once the occurrence's head is known
 					value: MatchValue::Split { branch: 0 },
-					next,
+					next: rest,
 				});
+				let split = *index;
+
+				Self::convert_value(&token.value, states, index)?;
+				let head = *index;
+				states[split].value = MatchValue::Split { branch: head };
+
+				if min == 0 {
+					// the loop itself can be skipped entirely
+					*index = split;
+				} else {
+					for _ in 0..min - 1 {
+						Self::convert_value(&token.value, states, index)?;
+					}
+				}
 			}
+			Repeat::AtMost(max) => {
+				for _ in 0..max {
+					let rest = *index;
+					Self::convert_value(&token.value, states, index)?;
+					let head = *index;
 
-			match &token.value {
-				MatchCharacter::Char(c) => {
 					push!(State {
-						value: MatchValue::Char(*c),
-						next: *index
+						value: MatchValue::Split { branch: rest },
+						next: head,
 					});
 				}
-				MatchCharacter::Wildcard => {
+			}
+			Repeat::Range(min, max) => {
+				for _ in 0..max - min {
+					let rest = *index;
+					Self::convert_value(&token.value, states, index)?;
+					let head = *index;
+
 					push!(State {
-						value: MatchValue::Wildcard,
-						next: *index
+						value: MatchValue::Split { branch: rest },
+						next: head,
 					});
 				}
-				MatchCharacter::String(tokens) => Self::convert_tokens(tokens, states, index),
-				MatchCharacter::Or(_, _) => todo!(),
-				MatchCharacter::Beginning | MatchCharacter::End => {
-					unreachable!("Regex boundary in convert_tokens")
+
+				for _ in 0..min {
+					Self::convert_value(&token.value, states, index)?;
 				}
 			}
+		}
 
-			if let Repeat::AtLeast(_) = token.repeat {
-				states[dbg!(next + 1)].next = *index;
-			}
+		Ok(())
+	}
 
-			if let Repeat::Optional | Repeat::AtLeast(0) = token.repeat {
-				push!(State {
-					value: MatchValue::Split { branch: next },
-					next: *index,
-				});
-			}
+	fn convert_tokens(tokens: &[Box<Token>], states: &mut Vec<State>, index: &mut usize) -> Result<()> {
+		for token in tokens.iter().rev() {
+			Self::convert_token(token, states, index)?;
 		}
+
+		Ok(())
 	}
 
-	fn from_tokens(mut tokens: &[Box<Token>]) -> Self {
+	fn from_tokens(parsed: &tokenize::Tokens) -> Result<Self> {
 		let mut states = vec![State {
 			next: 0,
 			value: MatchValue::Match,
 		}];
 		let mut index = 0;
 
-		let beginning_boundary = matches!(
-			tokens.first().map(|x| x.value == MatchCharacter::Beginning),
-			Some(true),
-		);
-
-		if beginning_boundary {
-			tokens = &tokens[1..];
-		}
-
-		let end_boundary = matches!(
-			tokens.last().map(|x| x.value == MatchCharacter::End),
-			Some(true)
-		);
-
-		if end_boundary {
-			tokens = &tokens[..tokens.len() - 1];
+		macro_rules! push {
+			($state: expr) => {{
+				if states.len() >= MAX_STATES {
+					return Err(Error::TooManyStates);
+				}
+				states.push($state);
+				// see the matching comment in convert_value
+				index = states.len() - 1;
+			}};
 		}
 
-		Self::convert_tokens(&tokens, &mut states, &mut index);
-
-		Self {
+		// `tokenize::Tokens` already stripped `^`/`$` off the token stream
+		// and recorded whether they were present; there's no boundary
+		// marker left in `tokens` to scan for here
+		let tokens: &[Box<Token>] = &parsed.tokens;
+		let beginning_boundary = parsed.beginning_boundary;
+		let end_boundary = parsed.end_boundary;
+
+		// slot 1 closes the whole-match group; built first so it chains to
+		// the `Match` state, matching the rest of this module's
+		// build-back-to-front convention
+		push!(State {
+			value: MatchValue::Save(1),
+			next: index,
+		});
+
+		Self::convert_tokens(tokens, &mut states, &mut index)?;
+
+		// slot 0 opens the whole-match group
+		push!(State {
+			value: MatchValue::Save(0),
+			next: index,
+		});
+
+		Ok(Self {
 			head: index,
 			states,
 			beginning_boundary,
 			end_boundary,
-		}
+			group_count: parsed.group_count,
+		})
 	}
 
 	pub fn test(&self, string: &str) -> bool {
+		self.captures(string).is_some()
+	}
+
+	/// Matches `self` against `string`, returning the char-index spans of
+	/// the whole match (group `0`) and any capturing groups, or `None` if it
+	/// doesn't match.
+	pub fn captures(&self, string: &str) -> Option<Captures> {
+		let slot_count = 2 * (self.group_count + 1);
 		let mut step = Step::new(&self.states);
+		let empty_slots: Slots = vec![None; slot_count].into_boxed_slice();
 
 		if self.beginning_boundary {
-			step.add_state(self.head);
+			step.add_state(self.head, empty_slots.clone(), 0);
 		}
 
-		for ch in string.chars() {
+		for (pos, ch) in string.chars().enumerate() {
 			if !self.beginning_boundary {
-				step.add_state(self.head);
+				step.add_state(self.head, empty_slots.clone(), pos);
 			}
 
-			if !self.end_boundary {
-				step.matched = false;
-			}
+			// a match only counts once it's confirmed by the closure formed
+			// after consuming the current character; without this reset, a
+			// thread that reached `Match` early (e.g. after the minimum
+			// repeats of a bounded quantifier) would freeze `step.matched`
+			// and block a longer, more-complete match from ever replacing it
+			step.matched = None;
 
-			step.step(ch);
-
-			println!("{:?}", step);
+			step.step(ch, pos + 1);
 		}
 
-		step.matched
+		step.matched.map(|slots| Captures::from_slots(&slots))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_alternation() {
+		let re = Regex::from_simple_expression("a|b").unwrap();
+		assert!(re.test("a"));
+		assert!(re.test("b"));
+		assert!(!re.test("c"));
+
+		let re = Regex::from_simple_expression("a|b|c").unwrap();
+		assert!(re.test("a"));
+		assert!(re.test("b"));
+		assert!(re.test("c"));
+
+		let re = Regex::from_simple_expression("ab|cd").unwrap();
+		assert!(re.test("ab"));
+		assert!(re.test("cd"));
+		assert!(!re.test("ad"));
+
+		let re = Regex::from_simple_expression("(a|b)+").unwrap();
+		assert!(re.test("a"));
+		assert!(re.test("b"));
+		assert!(re.test("ababab"));
+	}
+
+	#[test]
+	fn test_captures() {
+		let re = Regex::from_simple_expression("(a+)(b+)").unwrap();
+		let captures = re.captures("aaabb").unwrap();
+
+		assert_eq!(captures.get(0), Some((0, 5)));
+		assert_eq!(captures.get(1), Some((0, 3)));
+		assert_eq!(captures.get(2), Some((3, 5)));
+		assert_eq!(captures.len(), 3);
+		assert!(!captures.is_empty());
+
+		// a group inside an Optional that didn't participate reports None,
+		// not a stale span from a previous match attempt
+		let re = Regex::from_simple_expression("a(b)?c").unwrap();
+		let captures = re.captures("ac").unwrap();
+		assert_eq!(captures.get(0), Some((0, 2)));
+		assert_eq!(captures.get(1), None);
+	}
+
+	/// `(a*)*`/`(a?)+` nest unbounded quantifiers, so their epsilon-closure
+	/// contains a cycle back through the same states with no input consumed.
+	/// Without the `visited` guard in `Step::add_state`, these would recurse
+	/// until the stack overflows instead of returning a result; merely
+	/// returning (whatever the verdict) is the regression this guards.
+	#[test]
+	fn test_epsilon_cycle_does_not_recurse_forever() {
+		let re = Regex::from_simple_expression("(a*)*").unwrap();
+		assert!(re.test("aaaa"));
+		assert!(!re.test("b"));
+
+		let re = Regex::from_simple_expression("(a?)+b").unwrap();
+		assert!(re.test("b"));
+		assert!(re.test("aaab"));
+	}
+
+	/// `$` must reject trailing characters past whatever satisfied the
+	/// pattern first, and a capture must report the full greedy span, not
+	/// wherever the earliest thread happened to reach `Match`.
+	#[test]
+	fn test_end_boundary_rejects_trailing_input() {
+		let re = Regex::from_simple_expression("^ab$").unwrap();
+		assert!(re.test("ab"));
+		assert!(!re.test("abx"));
+
+		let re = Regex::from_simple_expression("^a{2,4}$").unwrap();
+		assert!(re.test("aaa"));
+		assert!(!re.test("aaaaa"));
+
+		let re = Regex::from_simple_expression("^(a+)$").unwrap();
+		let captures = re.captures("aaa").unwrap();
+		assert_eq!(captures.get(0), Some((0, 3)));
+		assert_eq!(captures.get(1), Some((0, 3)));
 	}
 }
@@ -4,8 +4,47 @@ use std::fmt;
 pub enum MatchCharacter {
 	Char(char),
 	Wildcard,
+	/// a plain sequence of tokens with no capturing semantics of its own,
+	/// e.g. one arm of an `Or`
 	String(Box<[Box<Token>]>),
+	/// a parenthesized, capturing group. `index` is its 0-based position
+	/// among the pattern's groups, in the order their `(` appears.
+	Group {
+		index: usize,
+		tokens: Box<[Box<Token>]>,
+	},
 	Or(Box<Token>, Box<Token>),
+	Class {
+		ranges: Box<[(char, char)]>,
+		named: Box<[NamedClass]>,
+		negated: bool,
+	},
+}
+
+/// A POSIX named character class, e.g. the `alpha` in `[[:alpha:]]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NamedClass {
+	Alpha,
+	Digit,
+	Alnum,
+	Space,
+	Upper,
+	Lower,
+	Punct,
+}
+
+impl NamedClass {
+	pub(crate) fn matches(&self, c: char) -> bool {
+		match self {
+			Self::Alpha => c.is_alphabetic(),
+			Self::Digit => c.is_ascii_digit(),
+			Self::Alnum => c.is_alphanumeric(),
+			Self::Space => c.is_whitespace(),
+			Self::Upper => c.is_uppercase(),
+			Self::Lower => c.is_lowercase(),
+			Self::Punct => c.is_ascii_punctuation(),
+		}
+	}
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -30,6 +69,8 @@ pub struct Tokens {
 	pub beginning_boundary: bool,
 	pub end_boundary: bool,
 	pub tokens: Vec<Box<Token>>,
+	/// the number of capturing groups (`(...)`) in the pattern
+	pub group_count: usize,
 }
 
 #[derive(Debug, PartialEq)]
@@ -38,6 +79,8 @@ pub enum Error {
 	InvalidModifierLocation { at: usize },
 	MismatchedGroup { at: usize },
 	UnexpectedBoundary { at: usize },
+	MalformedRepeat { at: usize },
+	MalformedClass { at: usize },
 }
 
 impl fmt::Display for Error {
@@ -53,6 +96,12 @@ impl fmt::Display for Error {
 			Self::UnexpectedBoundary { at } => {
 				write!(f, "Unexpected expression boundary (^ or $) at char {}", at)
 			}
+			Self::MalformedRepeat { at } => {
+				write!(f, "Malformed {{n,m}} repeat at char {}", at)
+			}
+			Self::MalformedClass { at } => {
+				write!(f, "Malformed bracket expression at char {}", at)
+			}
 		}
 	}
 }
@@ -63,8 +112,6 @@ pub type Result<T> = core::result::Result<T, Error>;
 
 impl Tokens {
 	pub fn tokenize_regex(expression: &str) -> Result<Self> {
-		let mut tokens = Vec::new();
-
 		let chars: Vec<_> = expression.chars().collect();
 
 		let beginning_boundary = matches!(chars.first(), Some('^'));
@@ -73,20 +120,66 @@ impl Tokens {
 		let mut i = beginning_boundary as usize;
 		let end = chars.len() - (end_boundary as usize);
 
-		while i < end {
-			tokens.push(Token::tokenize_one(&chars[..end], &mut i)?);
-		}
+		let mut group_count = 0;
+		let tokens = Token::tokenize_sequence(&chars, &mut i, end, &mut group_count)?;
 
 		Ok(Self {
 			tokens,
 			beginning_boundary,
 			end_boundary,
+			group_count,
 		})
 	}
 }
 
 impl Token {
-	fn tokenize_one(chars: &[char], i: &mut usize) -> Result<Box<Self>> {
+	/// Parses a concatenation of tokens, splitting at top-level (non-parenthesized)
+	/// `|` into alternatives. Stops at a `)` (without consuming it) or at `end`,
+	/// whichever comes first, so the same function drives both the top-level
+	/// expression and the inside of a group.
+	fn tokenize_sequence(
+		chars: &[char],
+		i: &mut usize,
+		end: usize,
+		group_count: &mut usize,
+	) -> Result<Vec<Box<Token>>> {
+		let mut alternatives = vec![Vec::new()];
+
+		while *i < end && chars[*i] != ')' {
+			if chars[*i] == '|' {
+				*i += 1;
+				alternatives.push(Vec::new());
+				continue;
+			}
+
+			alternatives
+				.last_mut()
+				.unwrap()
+				.push(Self::tokenize_one(chars, i, group_count)?);
+		}
+
+		let mut combined = alternatives.pop().unwrap();
+
+		while let Some(tokens) = alternatives.pop() {
+			combined = vec![Box::new(Token {
+				repeat: Repeat::Exactly(1),
+				value: MatchCharacter::Or(
+					Box::new(Token {
+						repeat: Repeat::Exactly(1),
+						value: MatchCharacter::String(tokens.into_boxed_slice()),
+					}),
+					Box::new(Token {
+						repeat: Repeat::Exactly(1),
+						value: MatchCharacter::String(combined.into_boxed_slice()),
+					}),
+				),
+			})];
+		}
+
+		Ok(combined)
+	}
+
+	fn tokenize_one(chars: &[char], i: &mut usize, group_count: &mut usize) -> Result<Box<Self>> {
 		if *i >= chars.len() {
 			return Err(Error::MissingToken { at: *i });
 		}
@@ -105,29 +198,30 @@ impl Token {
 			'?' | '*' | '+' => return Err(Error::InvalidModifierLocation { at: *i }),
 			'(' => {
 				*i += 1;
-				let mut vec = Vec::new();
+				let index = *group_count;
+				*group_count += 1;
 
-				// TODO check for correct i handling at boundaries
-				loop {
-					if *i >= chars.len() {
-						return Err(Error::MismatchedGroup { at: *i });
-					}
+				let vec = Self::tokenize_sequence(chars, i, chars.len(), group_count)?;
 
-					if chars[*i] == ')' {
-						break;
-					}
-
-					vec.push(Self::tokenize_one(chars, i)?);
+				if *i >= chars.len() {
+					return Err(Error::MismatchedGroup { at: *i });
 				}
 
-				MatchCharacter::String(vec.into_boxed_slice())
+				MatchCharacter::Group {
+					index,
+					tokens: vec.into_boxed_slice(),
+				}
 			}
 			')' => {
 				*i += 1;
 				return Err(Error::MismatchedGroup { at: *i });
 			}
 			'.' => MatchCharacter::Wildcard,
-			'|' => todo!(),
+			'|' => unreachable!("| is split out by tokenize_sequence before reaching tokenize_one"),
+			'[' => {
+				*i += 1;
+				Self::tokenize_class(chars, i)?
+			}
 			ch => MatchCharacter::Char(ch),
 		};
 
@@ -137,6 +231,7 @@ impl Token {
 			Some('?') => Repeat::Optional,
 			Some('+') => Repeat::AtLeast(1),
 			Some('*') => Repeat::AtLeast(0),
+			Some('{') => Self::tokenize_repeat_braces(chars, i)?,
 			_ => {
 				// don't consume
 				*i -= 1;
@@ -147,6 +242,142 @@ impl Token {
 
 		Ok(Box::new(Self { repeat, value }))
 	}
+
+	/// Parses a `{n}`, `{n,}`, `{,m}`, or `{n,m}` counted repeat. `*i` must
+	/// point at the opening `{`; on success it is left pointing at the
+	/// closing `}`, matching how the `?`/`+`/`*` arms leave `*i` on their
+	/// single-char modifier.
+	fn tokenize_repeat_braces(chars: &[char], i: &mut usize) -> Result<Repeat> {
+		let start = *i;
+		*i += 1;
+
+		let parse_digits = |chars: &[char], i: &mut usize| -> Option<usize> {
+			let digits_start = *i;
+
+			while matches!(chars.get(*i), Some(c) if c.is_ascii_digit()) {
+				*i += 1;
+			}
+
+			(*i > digits_start)
+				.then(|| chars[digits_start..*i].iter().collect::<String>())
+				.and_then(|s| s.parse().ok())
+		};
+
+		let min = parse_digits(chars, i);
+
+		let has_comma = matches!(chars.get(*i), Some(','));
+		if has_comma {
+			*i += 1;
+		}
+
+		let max = parse_digits(chars, i);
+
+		if chars.get(*i) != Some(&'}') {
+			return Err(Error::MalformedRepeat { at: start });
+		}
+
+		match (min, has_comma, max) {
+			(Some(n), false, None) => Ok(Repeat::Exactly(n)),
+			(Some(n), true, None) => Ok(Repeat::AtLeast(n)),
+			(None, true, Some(m)) => Ok(Repeat::AtMost(m)),
+			// n > m (e.g. `{5,2}`) is rejected here so callers can assume
+			// `Repeat::Range`'s bounds are always ordered
+			(Some(n), true, Some(m)) if n <= m => Ok(Repeat::Range(n, m)),
+			_ => Err(Error::MalformedRepeat { at: start }),
+		}
+	}
+
+	/// Parses a bracket expression (`[a-z]`, `[^0-9]`, `[[:alpha:]]`, ...).
+	/// `*i` must point just past the opening `[`; on success it is left
+	/// pointing at the closing `]`, matching how the `(` arm leaves `*i` on
+	/// the closing `)`.
+	fn tokenize_class(chars: &[char], i: &mut usize) -> Result<MatchCharacter> {
+		let start = *i - 1;
+
+		let negated = matches!(chars.get(*i), Some('^'));
+		if negated {
+			*i += 1;
+		}
+
+		let mut ranges = Vec::new();
+		let mut named = Vec::new();
+		let mut first = true;
+
+		loop {
+			match chars.get(*i) {
+				None => return Err(Error::MalformedClass { at: start }),
+				// a `]` right after `[` or `[^` is a literal `]`, not the end
+				Some(']') if !first => break,
+				Some('[') if matches!(chars.get(*i + 1), Some(':')) => {
+					*i += 2;
+					let name_start = *i;
+
+					while !matches!(chars.get(*i), Some(':')) {
+						*i += 1;
+						if *i >= chars.len() {
+							return Err(Error::MalformedClass { at: start });
+						}
+					}
+
+					let name: String = chars[name_start..*i].iter().collect();
+					*i += 1;
+
+					if chars.get(*i) != Some(&']') {
+						return Err(Error::MalformedClass { at: start });
+					}
+					*i += 1;
+
+					named.push(match name.as_str() {
+						"alpha" => NamedClass::Alpha,
+						"digit" => NamedClass::Digit,
+						"alnum" => NamedClass::Alnum,
+						"space" => NamedClass::Space,
+						"upper" => NamedClass::Upper,
+						"lower" => NamedClass::Lower,
+						"punct" => NamedClass::Punct,
+						_ => return Err(Error::MalformedClass { at: start }),
+					});
+				}
+				Some(_) => {
+					let from = Self::tokenize_class_char(chars, i)?;
+
+					let is_range = matches!(chars.get(*i), Some('-'))
+						&& !matches!(chars.get(*i + 1), Some(']') | None);
+
+					if is_range {
+						*i += 1;
+						let to = Self::tokenize_class_char(chars, i)?;
+						ranges.push((from, to));
+					} else {
+						ranges.push((from, from));
+					}
+				}
+			}
+
+			first = false;
+		}
+
+		Ok(MatchCharacter::Class {
+			ranges: ranges.into_boxed_slice(),
+			named: named.into_boxed_slice(),
+			negated,
+		})
+	}
+
+	fn tokenize_class_char(chars: &[char], i: &mut usize) -> Result<char> {
+		let c = match chars.get(*i) {
+			None => return Err(Error::MissingToken { at: *i }),
+			Some('\\') => {
+				*i += 1;
+				*chars.get(*i).ok_or(Error::MissingToken { at: *i })?
+			}
+			Some(&c) => c,
+		};
+
+		*i += 1;
+
+		Ok(c)
+	}
 }
 
 #[cfg(test)]
@@ -177,6 +408,7 @@ mod tests {
 				],
 				beginning_boundary: false,
 				end_boundary: false,
+				group_count: 0,
 			})
 		);
 
@@ -187,27 +419,96 @@ mod tests {
 					Tk!(Exactly(1), Char('a')),
 					Tk!(
 						AtLeast(1),
-						String(
-							vec![
+						Group {
+							index: 0,
+							tokens: vec![
 								Tk!(Exactly(1), Char('b')),
 								Tk!(
 									Optional,
-									String(
-										vec![
+									Group {
+										index: 1,
+										tokens: vec![
 											Tk!(Exactly(1), Char('c')),
 											Tk!(Exactly(1), Char('d'))
 										]
 										.into_boxed_slice()
-									)
+									}
 								)
 							]
 							.into_boxed_slice()
-						)
+						}
 					)
 				],
 				beginning_boundary: false,
-				end_boundary: false
+				end_boundary: false,
+				group_count: 2,
 			})
 		)
 	}
+
+	#[test]
+	fn test_tokenize_repeat_braces() {
+		assert_eq!(
+			Tokens::tokenize_regex("a{3}b{2,}c{,4}d{2,5}"),
+			Ok(Tokens {
+				tokens: vec![
+					Tk!(Exactly(3), Char('a')),
+					Tk!(AtLeast(2), Char('b')),
+					Tk!(AtMost(4), Char('c')),
+					Tk!(Range(2, 5), Char('d')),
+				],
+				beginning_boundary: false,
+				end_boundary: false,
+				group_count: 0,
+			})
+		);
+
+		assert_eq!(
+			Tokens::tokenize_regex("a{}"),
+			Err(Error::MalformedRepeat { at: 1 })
+		);
+
+		// n > m is malformed, not a silently-empty or underflowing range
+		assert_eq!(
+			Tokens::tokenize_regex("a{5,2}"),
+			Err(Error::MalformedRepeat { at: 1 })
+		);
+	}
+
+	#[test]
+	fn test_tokenize_class() {
+		assert_eq!(
+			Tokens::tokenize_regex("[a-zA-Z0-9_]"),
+			Ok(Tokens {
+				tokens: vec![Tk!(
+					Exactly(1),
+					Class {
+						ranges: vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')].into_boxed_slice(),
+						named: vec![].into_boxed_slice(),
+						negated: false,
+					}
+				)],
+				beginning_boundary: false,
+				end_boundary: false,
+				group_count: 0,
+			})
+		);
+
+		assert_eq!(
+			Tokens::tokenize_regex("[^[:digit:]]"),
+			Ok(Tokens {
+				tokens: vec![Tk!(
+					Exactly(1),
+					Class {
+						ranges: vec![].into_boxed_slice(),
+						named: vec![NamedClass::Digit].into_boxed_slice(),
+						negated: true,
+					}
+				)],
+				beginning_boundary: false,
+				end_boundary: false,
+				group_count: 0,
+			})
+		);
+	}
 }